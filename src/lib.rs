@@ -0,0 +1,629 @@
+//! Core library for signing and verifying Android OTA `payload.bin` files.
+//!
+//! This crate does the same work as the `avbpayloadsigner` CLI, but as a
+//! typed API that other Rust programs can embed directly instead of
+//! shelling out to the binary.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use avbroot::{
+    crypto::{self, PassphraseSource, RsaSigningKey},
+    format::{
+        payload::{PayloadHeader, PayloadReader, PayloadWriter},
+    },
+    stream::{self, FromReader},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use rsa::{
+    pkcs1v15::Pkcs1v15Sign,
+    sha2::{Digest, Sha256, Sha512},
+    RsaPrivateKey, RsaPublicKey,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+/// DER encoding of the SHA-256 `DigestInfo` prefix used by PKCS#1 v1.5
+/// signatures (RFC 8017 Appendix A.2.4).
+const SHA256_DIGEST_INFO_PREFIX: &[u8] = &[
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01,
+    0x05, 0x00, 0x04, 0x20,
+];
+
+/// DER encoding of the SHA-512 `DigestInfo` prefix used by PKCS#1 v1.5
+/// signatures (RFC 8017 Appendix A.2.4).
+const SHA512_DIGEST_INFO_PREFIX: &[u8] = &[
+    0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03,
+    0x05, 0x00, 0x04, 0x40,
+];
+
+/// Where the RSA private key material lives during signing.
+#[derive(Debug, Clone)]
+pub enum SigningBackend {
+    /// Sign using an in-process private key.
+    InProcess(RsaPrivateKey),
+    /// Delegate each signing operation to an external helper program.
+    External {
+        program: PathBuf,
+        public_key_path: PathBuf,
+        public_key: RsaPublicKey,
+        pass_file: Option<PathBuf>,
+        pass_env_var: Option<OsString>,
+    },
+}
+
+impl SigningBackend {
+    /// Loads a signing backend from the CLI's `--key`/`--signing-helper`/
+    /// `--pass-*` arguments: an in-process private key by default, or an
+    /// external helper backed by a public key when `signing_helper` is set.
+    pub fn load(
+        key_path: &Path,
+        signing_helper: Option<&Path>,
+        pass_file: Option<&Path>,
+        pass_env_var: Option<&OsStr>,
+    ) -> Result<Self> {
+        if let Some(program) = signing_helper {
+            let public_key = crypto::read_pem_public_key_file(key_path)
+                .with_context(|| format!("Failed to load public key: {key_path:?}"))?;
+
+            Ok(Self::External {
+                program: program.to_path_buf(),
+                public_key_path: key_path.to_path_buf(),
+                public_key,
+                pass_file: pass_file.map(Path::to_path_buf),
+                pass_env_var: pass_env_var.map(OsStr::to_os_string),
+            })
+        } else {
+            let passphrase_source = if let Some(var) = pass_env_var {
+                PassphraseSource::EnvVar(var.to_os_string())
+            } else if let Some(path) = pass_file {
+                PassphraseSource::File(path.to_path_buf())
+            } else {
+                PassphraseSource::Prompt(format!("Enter passphrase for {key_path:?}: "))
+            };
+
+            let private_key = crypto::read_pem_key_file(key_path, &passphrase_source)
+                .with_context(|| format!("Failed to load key: {key_path:?}"))?;
+
+            Ok(Self::InProcess(private_key))
+        }
+    }
+
+    fn public_key(&self) -> RsaPublicKey {
+        match self {
+            Self::InProcess(key) => key.to_public_key(),
+            Self::External { public_key, .. } => public_key.clone(),
+        }
+    }
+
+    /// Signs `digest` (a raw SHA-256 or SHA-512 hash), returning the raw
+    /// RSA signature.
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::InProcess(key) => sign_in_process(key, digest),
+            Self::External {
+                program,
+                public_key_path,
+                public_key,
+                pass_file,
+                pass_env_var,
+            } => sign_with_helper(
+                program,
+                public_key_path,
+                public_key,
+                pass_file.as_deref(),
+                pass_env_var.as_deref(),
+                digest,
+            ),
+        }
+    }
+}
+
+impl RsaSigningKey for SigningBackend {
+    fn public_key(&self) -> RsaPublicKey {
+        SigningBackend::public_key(self)
+    }
+
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        SigningBackend::sign(self, digest)
+    }
+}
+
+/// AVB algorithm name for the given key and digest size, e.g.
+/// `SHA256_RSA4096`.
+fn algorithm_name(key_bits: usize, digest_len: usize) -> Result<&'static str> {
+    Ok(match (digest_len, key_bits) {
+        (32, 2048) => "SHA256_RSA2048",
+        (32, 4096) => "SHA256_RSA4096",
+        (64, 2048) => "SHA512_RSA2048",
+        (64, 4096) => "SHA512_RSA4096",
+        _ => bail!(
+            "Unsupported key size ({key_bits}-bit) / digest size ({digest_len}-byte) combination"
+        ),
+    })
+}
+
+fn pkcs1v15_scheme(digest: &[u8]) -> Result<Pkcs1v15Sign> {
+    Ok(match digest.len() {
+        32 => Pkcs1v15Sign::new::<Sha256>(),
+        64 => Pkcs1v15Sign::new::<Sha512>(),
+        n => bail!("Unsupported digest length: {n} bytes"),
+    })
+}
+
+fn sign_in_process(key: &RsaPrivateKey, digest: &[u8]) -> Result<Vec<u8>> {
+    key.sign(pkcs1v15_scheme(digest)?, digest)
+        .context("In-process RSA signing failed")
+}
+
+/// Builds the PKCS#1 v1.5 `DigestInfo` DER encoding for `digest`.
+fn digest_info_der(digest: &[u8]) -> Result<Vec<u8>> {
+    let prefix = match digest.len() {
+        32 => SHA256_DIGEST_INFO_PREFIX,
+        64 => SHA512_DIGEST_INFO_PREFIX,
+        n => bail!("Unsupported digest length: {n} bytes"),
+    };
+
+    let mut der = Vec::with_capacity(prefix.len() + digest.len());
+    der.extend_from_slice(prefix);
+    der.extend_from_slice(digest);
+    Ok(der)
+}
+
+fn sign_with_helper(
+    program: &Path,
+    public_key_path: &Path,
+    public_key: &RsaPublicKey,
+    pass_file: Option<&Path>,
+    pass_env_var: Option<&OsStr>,
+    digest: &[u8],
+) -> Result<Vec<u8>> {
+    let algorithm = algorithm_name(public_key.size() * 8, digest.len())?;
+
+    let mut command = Command::new(program);
+    command.arg(algorithm).arg(public_key_path);
+
+    if let Some(path) = pass_file {
+        command.arg("file").arg(path);
+    } else if let Some(var) = pass_env_var {
+        command.arg("env").arg(var);
+    }
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn signing helper: {program:?}"))?;
+
+    let digest_info = digest_info_der(digest)?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open stdin of signing helper: {program:?}"))?
+        .write_all(&digest_info)
+        .with_context(|| format!("Failed to write digest to signing helper: {program:?}"))?;
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to read output of signing helper: {program:?}"))?;
+
+    if !output.status.success() {
+        bail!("Signing helper {program:?} exited with {}", output.status);
+    }
+
+    let signature = output.stdout;
+
+    public_key
+        .verify(pkcs1v15_scheme(digest)?, digest, &signature)
+        .with_context(|| {
+            format!("Signature returned by signing helper {program:?} does not match {public_key_path:?}")
+        })?;
+
+    Ok(signature)
+}
+
+/// A [`Read`] wrapper that advances a progress bar by the number of bytes
+/// read through it. A no-op when `progress` is `None` (i.e. `--quiet`).
+struct ProgressReader<'a, R> {
+    inner: R,
+    progress: Option<&'a ProgressBar>,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(progress) = self.progress {
+            progress.inc(n as u64);
+        }
+        Ok(n)
+    }
+}
+
+fn new_progress_bar(total_bytes: u64) -> Result<ProgressBar> {
+    let progress = ProgressBar::new(total_bytes);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+        )
+        .context("Failed to build progress bar style")?
+        .progress_chars("#>-"),
+    );
+    Ok(progress)
+}
+
+/// The parsed contents of the `payload_properties.txt` companion file that
+/// accompanies a signed `payload.bin` inside an OTA zip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadProperties {
+    pub file_hash: String,
+    pub file_size: u64,
+    pub metadata_hash: String,
+    pub metadata_size: u64,
+}
+
+impl PayloadProperties {
+    fn parse(properties: &str) -> Result<Self> {
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut metadata_hash = None;
+        let mut metadata_size = None;
+
+        for line in properties.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            match key {
+                "FILE_HASH" => file_hash = Some(value.to_owned()),
+                "FILE_SIZE" => {
+                    file_size = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid FILE_SIZE: {value}"))?,
+                    )
+                }
+                "METADATA_HASH" => metadata_hash = Some(value.to_owned()),
+                "METADATA_SIZE" => {
+                    metadata_size = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid METADATA_SIZE: {value}"))?,
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            file_hash: file_hash
+                .ok_or_else(|| anyhow!("Missing FILE_HASH in payload properties"))?,
+            file_size: file_size
+                .ok_or_else(|| anyhow!("Missing FILE_SIZE in payload properties"))?,
+            metadata_hash: metadata_hash
+                .ok_or_else(|| anyhow!("Missing METADATA_HASH in payload properties"))?,
+            metadata_size: metadata_size
+                .ok_or_else(|| anyhow!("Missing METADATA_SIZE in payload properties"))?,
+        })
+    }
+
+    /// Renders back to the `payload_properties.txt` line format.
+    pub fn to_properties_string(&self) -> String {
+        format!(
+            "FILE_HASH={}\nFILE_SIZE={}\nMETADATA_HASH={}\nMETADATA_SIZE={}\n",
+            self.file_hash, self.file_size, self.metadata_hash, self.metadata_size,
+        )
+    }
+}
+
+/// The result of a successful [`sign_payload`] call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignOutcome {
+    pub properties: PayloadProperties,
+    pub metadata_size: u64,
+}
+
+/// Sign a (potentially unsigned) payload without making any other
+/// modifications to it.
+pub fn sign_payload(
+    unsigned_payload: &Path,
+    writer: impl Write + Seek,
+    key: &SigningBackend,
+    quiet: bool,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<SignOutcome> {
+    let inc_raw_reader = File::open(unsigned_payload)
+        .with_context(|| format!("Failed to open for reading: {unsigned_payload:?}"))?;
+    let inc_reader = BufReader::new(inc_raw_reader);
+
+    sign_payload_core(inc_reader, 0, writer, key, quiet, cancel_signal)
+        .with_context(|| format!("Failed to sign payload: {unsigned_payload:?}"))
+}
+
+/// Core of [`sign_payload`], generalized over where the unsigned payload's
+/// bytes start (`base_offset`) so it can also operate on a `payload.bin`
+/// embedded at some offset inside a larger file, such as an OTA zip.
+fn sign_payload_core(
+    mut inc_reader: impl Read + Seek,
+    base_offset: u64,
+    writer: impl Write,
+    key: &SigningBackend,
+    quiet: bool,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<SignOutcome> {
+    let inc_header =
+        PayloadHeader::from_reader(&mut inc_reader).context("Failed to parse payload header")?;
+
+    let total_bytes: u64 = inc_header
+        .manifest
+        .partitions
+        .iter()
+        .flat_map(|p| &p.operations)
+        .filter_map(|op| op.data_length)
+        .sum();
+
+    let progress = if quiet {
+        None
+    } else {
+        Some(new_progress_bar(total_bytes)?)
+    };
+
+    let mut payload_writer = PayloadWriter::new(writer, inc_header.clone(), key.clone())
+        .context("Failed to write payload header")?;
+
+    while payload_writer
+        .begin_next_operation()
+        .context("Failed to begin next payload blob entry")?
+    {
+        let name = payload_writer.partition().unwrap().partition_name.clone();
+        let operation = payload_writer.operation().unwrap();
+
+        let Some(data_length) = operation.data_length else {
+            // Otherwise, this is a ZERO/DISCARD operation.
+            continue;
+        };
+
+        // Copy from the original payload.
+        let pi = payload_writer.partition_index().unwrap();
+        let oi = payload_writer.operation_index().unwrap();
+        let orig_partition = &inc_header.manifest.partitions[pi];
+        let orig_operation = &orig_partition.operations[oi];
+
+        let data_offset = orig_operation
+            .data_offset
+            .and_then(|o| o.checked_add(inc_header.blob_offset))
+            .and_then(|o| o.checked_add(base_offset))
+            .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
+
+        inc_reader
+            .seek(SeekFrom::Start(data_offset))
+            .with_context(|| format!("Failed to seek original payload to {data_offset}"))?;
+
+        let mut source = ProgressReader {
+            inner: &mut inc_reader,
+            progress: progress.as_ref(),
+        };
+
+        stream::copy_n(&mut source, &mut payload_writer, data_length, cancel_signal)
+            .with_context(|| format!("Failed to copy from original payload: {name}"))?;
+    }
+
+    if let Some(progress) = &progress {
+        progress.finish_and_clear();
+    }
+
+    let (_, properties, metadata_size) = payload_writer
+        .finish()
+        .context("Failed to finalize payload")?;
+
+    Ok(SignOutcome {
+        properties: PayloadProperties::parse(&properties)?,
+        metadata_size,
+    })
+}
+
+/// Signs the `payload.bin` inside an OTA zip, writing a new zip to
+/// `output_zip` with the re-signed payload and a `payload_properties.txt`
+/// regenerated from the signing outcome. Every other zip entry is copied
+/// through unchanged. `input_zip` itself is only read, never modified.
+pub fn sign_ota_zip(
+    input_zip: &Path,
+    output_zip: &Path,
+    key: &SigningBackend,
+    quiet: bool,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<SignOutcome> {
+    let mut archive = ZipArchive::new(BufReader::new(
+        File::open(input_zip).with_context(|| format!("Failed to open for reading: {input_zip:?}"))?,
+    ))
+    .with_context(|| format!("Failed to read zip central directory: {input_zip:?}"))?;
+
+    let payload_index = archive
+        .index_for_name("payload.bin")
+        .ok_or_else(|| anyhow!("{input_zip:?} does not contain payload.bin"))?;
+
+    let payload_data_start = {
+        let payload_entry = archive
+            .by_index(payload_index)
+            .with_context(|| format!("Failed to read payload.bin entry in {input_zip:?}"))?;
+
+        if payload_entry.compression() != CompressionMethod::Stored {
+            bail!("payload.bin in {input_zip:?} is compressed; expected it to be stored");
+        }
+
+        payload_entry.data_start()
+    };
+
+    let mut zip_writer = ZipWriter::new(
+        File::create(output_zip).with_context(|| format!("Failed to create: {output_zip:?}"))?,
+    );
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    // OTA payload.bin routinely exceeds 4 GiB; without this, the zip crate
+    // either rejects the entry at `finish()` or silently truncates its size
+    // field once local/central headers are patched in.
+    let payload_options = options.large_file(true);
+
+    let mut outcome = None;
+
+    for i in 0..archive.len() {
+        let entry = archive
+            .by_index(i)
+            .with_context(|| format!("Failed to read zip entry #{i} in {input_zip:?}"))?;
+        let name = entry.name().to_owned();
+
+        if i == payload_index {
+            let mut payload_reader = BufReader::new(
+                File::open(input_zip)
+                    .with_context(|| format!("Failed to open for reading: {input_zip:?}"))?,
+            );
+            payload_reader
+                .seek(SeekFrom::Start(payload_data_start))
+                .with_context(|| format!("Failed to seek to payload.bin in {input_zip:?}"))?;
+
+            zip_writer
+                .start_file(&name, payload_options)
+                .with_context(|| format!("Failed to start zip entry: {name}"))?;
+
+            outcome = Some(sign_payload_core(
+                &mut payload_reader,
+                payload_data_start,
+                &mut zip_writer,
+                key,
+                quiet,
+                cancel_signal,
+            )?);
+        } else if name == "payload_properties.txt" {
+            // Regenerated below from the signing outcome instead of copied.
+        } else {
+            zip_writer
+                .raw_copy_file(entry)
+                .with_context(|| format!("Failed to copy zip entry: {name}"))?;
+        }
+    }
+
+    let outcome =
+        outcome.ok_or_else(|| anyhow!("{input_zip:?} does not contain payload.bin"))?;
+
+    zip_writer
+        .start_file("payload_properties.txt", options)
+        .context("Failed to start zip entry: payload_properties.txt")?;
+    zip_writer
+        .write_all(outcome.properties.to_properties_string().as_bytes())
+        .context("Failed to write payload_properties.txt")?;
+
+    zip_writer.finish().context("Failed to finalize output zip")?;
+
+    Ok(outcome)
+}
+
+/// Checks a signed payload against `public_key` without rewriting it,
+/// re-deriving each partition operation's digest from scratch and then
+/// checking the overall payload signature.
+pub fn verify_payload(signed_payload: &Path, public_key: &RsaPublicKey) -> Result<()> {
+    let raw_reader = File::open(signed_payload)
+        .with_context(|| format!("Failed to open for reading: {signed_payload:?}"))?;
+    let mut reader = BufReader::new(raw_reader);
+    let header = PayloadHeader::from_reader(&mut reader)
+        .with_context(|| format!("Failed to parse payload header: {signed_payload:?}"))?;
+
+    let mut payload_reader = PayloadReader::new(reader, header, public_key.clone())
+        .context("Failed to open payload for verification")?;
+
+    while payload_reader
+        .begin_next_operation()
+        .context("Failed to begin next payload blob entry")?
+    {
+        let pi = payload_reader.partition_index().unwrap();
+        let oi = payload_reader.operation_index().unwrap();
+        let name = payload_reader.partition().unwrap().partition_name.clone();
+
+        let Some(data_length) = payload_reader.operation().unwrap().data_length else {
+            // Otherwise, this is a ZERO/DISCARD operation.
+            continue;
+        };
+
+        stream::copy_n(
+            &mut payload_reader,
+            &mut io::sink(),
+            data_length,
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .with_context(|| format!("Digest mismatch in partition #{pi} operation #{oi} ({name})"))?;
+    }
+
+    payload_reader
+        .finish()
+        .context("Payload signature verification failed")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_info_der_sha256() {
+        let digest = [0x11; 32];
+        let der = digest_info_der(&digest).unwrap();
+
+        assert_eq!(&der[..SHA256_DIGEST_INFO_PREFIX.len()], SHA256_DIGEST_INFO_PREFIX);
+        assert_eq!(&der[SHA256_DIGEST_INFO_PREFIX.len()..], &digest[..]);
+        // DigestInfo length byte covers everything after the outer tag+length.
+        assert_eq!(der[1] as usize, der.len() - 2);
+    }
+
+    #[test]
+    fn digest_info_der_sha512() {
+        let digest = [0x22; 64];
+        let der = digest_info_der(&digest).unwrap();
+
+        assert_eq!(&der[..SHA512_DIGEST_INFO_PREFIX.len()], SHA512_DIGEST_INFO_PREFIX);
+        assert_eq!(&der[SHA512_DIGEST_INFO_PREFIX.len()..], &digest[..]);
+        assert_eq!(der[1] as usize, der.len() - 2);
+    }
+
+    #[test]
+    fn digest_info_der_rejects_unknown_length() {
+        assert!(digest_info_der(&[0; 20]).is_err());
+    }
+
+    #[test]
+    fn algorithm_name_matches_avb_table() {
+        assert_eq!(algorithm_name(2048, 32).unwrap(), "SHA256_RSA2048");
+        assert_eq!(algorithm_name(4096, 32).unwrap(), "SHA256_RSA4096");
+        assert_eq!(algorithm_name(2048, 64).unwrap(), "SHA512_RSA2048");
+        assert_eq!(algorithm_name(4096, 64).unwrap(), "SHA512_RSA4096");
+    }
+
+    #[test]
+    fn algorithm_name_rejects_unsupported_combination() {
+        assert!(algorithm_name(3072, 32).is_err());
+        assert!(algorithm_name(2048, 20).is_err());
+    }
+
+    #[test]
+    fn payload_properties_round_trip() {
+        let properties = PayloadProperties {
+            file_hash: "abc123".to_owned(),
+            file_size: 42,
+            metadata_hash: "def456".to_owned(),
+            metadata_size: 7,
+        };
+
+        let rendered = properties.to_properties_string();
+        let parsed = PayloadProperties::parse(&rendered).unwrap();
+
+        assert_eq!(parsed, properties);
+    }
+
+    #[test]
+    fn payload_properties_parse_rejects_missing_field() {
+        assert!(PayloadProperties::parse("FILE_HASH=abc123\nFILE_SIZE=42\n").is_err());
+    }
+}
@@ -1,33 +1,45 @@
 use std::{
     ffi::OsString,
-    fs::{File},
-    io::{BufReader, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
-    sync::{atomic::AtomicBool, Arc},
-};
-
-use anyhow::{anyhow, Context, Result};
-use avbroot::{
-    crypto::{self, PassphraseSource},
-    format::{
-        payload::{PayloadHeader, PayloadWriter},
+    fs::{self, File},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
     },
-    stream::{self, FromReader},
 };
-use clap::Parser;
-use rsa::RsaPrivateKey;
+
+use anyhow::{Context, Result};
+use avb_payload_signer::{sign_ota_zip, sign_payload, verify_payload, SigningBackend};
+use avbroot::crypto;
+use clap::{Parser, Subcommand};
 
 #[derive(Debug, Parser)]
 struct Cli {
-    /// Path to old unsigned payload.bin
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Sign a payload.bin, or re-sign an already signed one
+    Sign(SignArgs),
+    /// Check a signed payload.bin against a public key
+    Verify(VerifyArgs),
+}
+
+#[derive(Debug, Parser)]
+struct SignArgs {
+    /// Path to old unsigned payload.bin, or an OTA zip containing one
     #[arg(long, value_name = "FILE", value_parser)]
     input: PathBuf,
 
-    /// Path to output signed payload.bin
+    /// Path to output signed payload.bin, or OTA zip if --input is one
     #[arg(long, value_name = "FILE", value_parser)]
     output: PathBuf,
 
-    /// Private key for signing the payload.bin.
+    /// Private key for signing the payload.bin. With --signing-helper, this
+    /// is instead the corresponding *public* key, since the private key
+    /// never needs to be loaded into this process.
     #[arg(short, long, value_name = "FILE", value_parser)]
     key: PathBuf,
 
@@ -38,101 +50,97 @@ struct Cli {
     /// Text file containing the private key passphrase.
     #[arg(long, value_name = "FILE", value_parser, group = "passphrase")]
     pass_file: Option<PathBuf>,
-}
 
-/// Sign a (potentially unsigned) payload without making any other
-/// modifications to it.
-fn sign_payload(
-    unsigned_payload: &Path,
-    writer: impl Write,
-    key: &RsaPrivateKey,
-) -> Result<(String, u64)> {
-    let inc_raw_reader = File::open(unsigned_payload)
-        .with_context(|| format!("Failed to open for reading: {unsigned_payload:?}"))?;
-    let mut inc_reader = BufReader::new(inc_raw_reader);
-    let inc_header = PayloadHeader::from_reader(&mut inc_reader)
-        .with_context(|| format!("Failed to parse payload header: {unsigned_payload:?}"))?;
-
-    let mut payload_writer = PayloadWriter::new(writer, inc_header.clone(), key.clone())
-        .context("Failed to write payload header")?;
-
-    while payload_writer
-        .begin_next_operation()
-        .context("Failed to begin next payload blob entry")?
-    {
-        let name = payload_writer.partition().unwrap().partition_name.clone();
-        let operation = payload_writer.operation().unwrap();
-
-        let Some(data_length) = operation.data_length else {
-            // Otherwise, this is a ZERO/DISCARD operation.
-            continue;
-        };
-
-        // Copy from the original payload.
-        let pi = payload_writer.partition_index().unwrap();
-        let oi = payload_writer.operation_index().unwrap();
-        let orig_partition = &inc_header.manifest.partitions[pi];
-        let orig_operation = &orig_partition.operations[oi];
-
-        let data_offset = orig_operation
-            .data_offset
-            .and_then(|o| o.checked_add(inc_header.blob_offset))
-            .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
-
-        inc_reader
-            .seek(SeekFrom::Start(data_offset))
-            .with_context(|| format!("Failed to seek original payload to {data_offset}"))?;
-
-        stream::copy_n(
-            &mut inc_reader,
-            &mut payload_writer,
-            data_length,
-            &Arc::new(AtomicBool::new(false)),
-        )
-        .with_context(|| format!("Failed to copy from original payload: {name}"))?;
-    }
+    /// External program used to perform the RSA signing operation instead
+    /// of an in-process private key, e.g. to delegate to an HSM or a
+    /// remote KMS that never exposes private key material on disk. The
+    /// program is invoked as `<program> <algorithm> <public-key-path>`
+    /// (plus `file <path>` or `env <name>` when a passphrase source is
+    /// given), is fed the PKCS#1 v1.5 DigestInfo DER encoding of the
+    /// payload digest on stdin, and must write the raw RSA signature to
+    /// stdout.
+    #[arg(long, value_name = "PROGRAM", value_parser)]
+    signing_helper: Option<PathBuf>,
+
+    /// Suppress the progress bar (e.g. for scripted/non-interactive use)
+    #[arg(long)]
+    quiet: bool,
+}
 
-    let (_, p, m) = payload_writer
-        .finish()
-        .context("Failed to finalize payload")?;
+#[derive(Debug, Parser)]
+struct VerifyArgs {
+    /// Path to signed payload.bin to check
+    #[arg(long, value_name = "FILE", value_parser)]
+    input: PathBuf,
 
-    Ok((p, m))
+    /// Public key the payload.bin should be signed with
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    key: PathBuf,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn run_sign(args: SignArgs) -> Result<()> {
+    let key = SigningBackend::load(
+        &args.key,
+        args.signing_helper.as_deref(),
+        args.pass_file.as_deref(),
+        args.pass_env_var.as_deref(),
+    )?;
+
+    println!("Signing the OTA payload, please wait...");
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let ctrlc_signal = cancel_signal.clone();
+    ctrlc::set_handler(move || ctrlc_signal.store(true, Ordering::SeqCst))
+        .context("Failed to install Ctrl-C handler")?;
 
-    let mut properties = None;
-    let mut payload_metadata_size = None;
+    let is_ota_zip = args
+        .input
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
 
-    let passphrase_source = if let Some(v) = &cli.pass_env_var {
-        PassphraseSource::EnvVar(v.clone())
-    } else if let Some(p) = &cli.pass_file {
-        PassphraseSource::File(p.clone())
+    let result = if is_ota_zip {
+        sign_ota_zip(&args.input, &args.output, &key, args.quiet, &cancel_signal)
     } else {
-        PassphraseSource::Prompt(format!("Enter passphrase for {:?}: ", cli.key))
+        File::create(&args.output)
+            .with_context(|| format!("Failed to create: {:?}", args.output))
+            .and_then(|mut writer| {
+                sign_payload(&args.input, &mut writer, &key, args.quiet, &cancel_signal)
+            })
     };
 
-    let key = crypto::read_pem_key_file(&cli.key, &passphrase_source)
-        .with_context(|| format!("Failed to load key: {:?}", cli.key))?;
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let _ = fs::remove_file(&args.output);
+            return Err(e);
+        }
+    };
 
-    println!("Signing the OTA payload, please wait...");
+    println!("Properties: {:?}", outcome.properties.to_properties_string());
+    println!("Payload_metadata_size: {:?}", outcome.metadata_size);
 
-    let unsigned_payload = Path::new(&cli.input);
-    let mut writer = File::create(&cli.output)?;
+    Ok(())
+}
 
-    let (p, m) = sign_payload(&unsigned_payload, &mut writer, &key)?;
+fn run_verify(args: VerifyArgs) -> Result<()> {
+    let public_key = crypto::read_pem_public_key_file(&args.key)
+        .with_context(|| format!("Failed to load public key: {:?}", args.key))?;
 
-    properties = Some(p);
-    payload_metadata_size = Some(m);
+    println!("Verifying the OTA payload, please wait...");
 
-    if let Some(props) = properties {
-        println!("Properties: {:?}", props);
-    }
+    verify_payload(&args.input, &public_key)?;
 
-    if let Some(size) = payload_metadata_size {
-        println!("Payload_metadata_size: {:?}", size);
-    }
+    println!("Payload signature and digests are valid.");
 
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Sign(args) => run_sign(args),
+        Command::Verify(args) => run_verify(args),
+    }
+}